@@ -1,18 +1,22 @@
 //! Implements a command for uploading dsym files.
 use std::fs;
 use std::env;
+use std::mem;
 use std::path::{Path, PathBuf};
 use std::fs::File;
-use std::io::{Write, Seek};
+use std::io::{Read, Write, Seek, SeekFrom};
 use std::ffi::OsStr;
 use std::cell::RefCell;
 use std::iter::Fuse;
-use std::rc::Rc;
+use std::sync::Mutex;
+use std::sync::mpsc::sync_channel;
 use std::collections::HashSet;
 
 use clap::{App, Arg, ArgMatches};
 use walkdir::{WalkDir, Iter as WalkDirIter};
 use zip;
+use rayon;
+use serde_json;
 use uuid::Uuid;
 use indicatif::{ProgressBar, ProgressStyle, style};
 
@@ -21,73 +25,341 @@ use api::{Api, DSymFile};
 use utils::{ArgExt, TempFile, get_sha1_checksum,
             is_zip_file, validate_uuid, copy_with_progress,
             make_byte_progress_bar};
+use utils::dsym_cache::{DSymCache, mtime_to_i64};
+use utils::chunking::{self, Chunk};
+use utils::macho_validate;
 use config::Config;
 use xcode;
 use macho;
 
 const BATCH_SIZE: usize = 12;
 
+// How many candidate files are handed to the rayon thread pool at once.
+// Each chunk is scanned fully in parallel before its results are folded
+// into a batch, so this also bounds how far ahead of the main loop the
+// scan can race.
+const SCAN_CHUNK_SIZE: usize = 32;
+
 #[derive(Debug)]
 enum DSymVar {
     FsFile(PathBuf),
-    ZipFile(Rc<RefCell<Option<zip::ZipArchive<fs::File>>>>, usize),
+    // Zip archives are reopened per use rather than shared through a
+    // single handle, since the MachO scan for each entry now happens on
+    // whichever worker thread picks it up.
+    ZipFile(PathBuf, usize),
 }
 
 #[derive(Debug)]
 struct DSymRef {
     var: DSymVar,
     arc_name: String,
+    // Same key the scan/checksum cache uses for this file, kept around so
+    // the chunk manifest can be looked up and stored under the same entry.
+    cache_key: PathBuf,
+    mtime: i64,
     checksum: String,
     size: u64,
     uuids: HashSet<Uuid>,
+    // Filled in just before upload, for files large enough that chunked
+    // deduplication is worth the overhead. `None` means "upload whole".
+    manifest: Option<Vec<Chunk>>,
 }
 
 impl DSymRef {
+    // Not every zip reader (or every build of this crate's zip dependency)
+    // understands every codec, so fall back to the always available
+    // deflate method if starting the file with the requested one fails.
+    // Shared by `add_to_archive` and `add_chunks_to_archive` so neither one
+    // can drift out of sync and hard-error on an unsupported codec.
+    fn start_archive_file<W: Write + Seek>(zip: &mut zip::ZipWriter<W>, name: String,
+                                           options: zip::write::FileOptions) -> Result<()> {
+        if zip.start_file(name.clone(), options).is_err() {
+            zip.start_file(name, zip::write::FileOptions::default())?;
+        }
+        Ok(())
+    }
+
     pub fn add_to_archive<W: Write + Seek>(&self, mut zip: &mut zip::ZipWriter<W>,
-                                           pb: &ProgressBar) -> Result<()> {
-        zip.start_file(self.arc_name.clone(), zip::write::FileOptions::default())?;
+                                           pb: &ProgressBar,
+                                           options: zip::write::FileOptions) -> Result<()> {
+        Self::start_archive_file(zip, self.arc_name.clone(), options)?;
         match self.var {
             DSymVar::FsFile(ref p) => {
                 copy_with_progress(pb, &mut File::open(&p)?, &mut zip)?;
             }
-            DSymVar::ZipFile(ref rc, idx) => {
-                let rc = rc.clone();
-                let mut opt_archive = rc.borrow_mut();
-                if let Some(ref mut archive) = *opt_archive {
-                    let mut af = archive.by_index(idx)?;
-                    copy_with_progress(pb, &mut af, &mut zip)?;
-                } else {
-                    panic!("zip file went away");
+            DSymVar::ZipFile(ref zip_path, idx) => {
+                let mut archive = zip::ZipArchive::new(fs::File::open(zip_path)?)?;
+                let mut af = archive.by_index(idx)?;
+                copy_with_progress(pb, &mut af, &mut zip)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds only the chunks of this file the server doesn't already have,
+    /// plus the small manifest describing how to reassemble them, instead
+    /// of the whole file.  Used instead of `add_to_archive` once a manifest
+    /// has been computed for this ref.
+    ///
+    /// `written_chunks` is shared across every ref in the batch: a digest
+    /// only gets written to `Chunks/<digest>` the first time it's seen,
+    /// whether that's because two refs share a chunk or because one
+    /// manifest repeats a digest (e.g. a run of zero-padding) more than
+    /// once. Without that, the archive would end up with two zip entries
+    /// of the same name, which is both wasted bandwidth and a malformed
+    /// zip.
+    pub fn add_chunks_to_archive<W: Write + Seek>(&self, zip: &mut zip::ZipWriter<W>,
+                                                  pb: &ProgressBar,
+                                                  options: zip::write::FileOptions,
+                                                  manifest: &[Chunk],
+                                                  missing_chunks: &HashSet<String>,
+                                                  written_chunks: &mut HashSet<String>)
+        -> Result<()>
+    {
+        // Chunks are listed in ascending offset order, so each one can be
+        // read as it's reached instead of buffering the whole file/entry in
+        // memory up front -- the point of chunking a multi-hundred-megabyte
+        // dSYM in the first place.
+        match self.var {
+            DSymVar::FsFile(ref p) => {
+                let mut f = File::open(p)?;
+                for chunk in manifest {
+                    pb.inc(chunk.len);
+                    if !missing_chunks.contains(&chunk.checksum) ||
+                       !written_chunks.insert(chunk.checksum.clone()) {
+                        continue;
+                    }
+                    f.seek(SeekFrom::Start(chunk.offset))?;
+                    let mut buf = vec![0u8; chunk.len as usize];
+                    f.read_exact(&mut buf)?;
+                    Self::start_archive_file(zip, format!("Chunks/{}", chunk.checksum), options)?;
+                    zip.write_all(&buf)?;
+                }
+            }
+            DSymVar::ZipFile(ref zip_path, idx) => {
+                // A zip entry's decompressing reader can't seek to an
+                // arbitrary chunk offset without redecompressing from the
+                // start, so instead read it once, sequentially, consuming
+                // exactly each chunk's length as the manifest (already in
+                // offset order) is walked.
+                let mut archive = zip::ZipArchive::new(fs::File::open(zip_path)?)?;
+                let mut entry = archive.by_index(idx)?;
+                let mut buf = Vec::new();
+                for chunk in manifest {
+                    pb.inc(chunk.len);
+                    buf.resize(chunk.len as usize, 0);
+                    entry.read_exact(&mut buf)?;
+                    if !missing_chunks.contains(&chunk.checksum) ||
+                       !written_chunks.insert(chunk.checksum.clone()) {
+                        continue;
+                    }
+                    Self::start_archive_file(zip, format!("Chunks/{}", chunk.checksum), options)?;
+                    zip.write_all(&buf)?;
                 }
             }
         }
+
+        Self::start_archive_file(zip, format!("{}.manifest.json", self.arc_name), options)?;
+        serde_json::to_writer(zip, manifest)?;
+
         Ok(())
     }
 }
 
+/// A single file awaiting a MachO/checksum scan: either a plain file on
+/// disk or one entry of a zip archive, identified by index.
+#[derive(Debug, Clone)]
+enum ScanTask {
+    FsFile(PathBuf),
+    ZipEntry(PathBuf, usize),
+}
+
+impl ScanTask {
+    fn display_name(&self) -> Option<String> {
+        let path = match *self {
+            ScanTask::FsFile(ref p) => p,
+            ScanTask::ZipEntry(ref p, _) => p,
+        };
+        path.file_name().and_then(|x| x.to_str()).map(|s| s.to_string())
+    }
+}
+
+/// A MachO/dSYM candidate whose header was present but the file turned out
+/// to be truncated or otherwise malformed, as opposed to not being an
+/// object file at all (which is silently skipped).  Typically produced by
+/// an interrupted or still-in-progress build.
+#[derive(Debug, Clone)]
+struct BrokenFile {
+    path: String,
+    error_string: String,
+}
+
+/// Bundles the two pieces of shared, cross-thread state a scan needs: the
+/// checksum/UUID cache and the list of broken files found so far.  Cheap
+/// to copy since both fields are just references.
+#[derive(Clone, Copy)]
+struct ScanCtx<'a> {
+    cache: &'a Mutex<DSymCache>,
+    broken: &'a Mutex<Vec<BrokenFile>>,
+}
+
+/// Records a MachO parse failure as a broken file only when it reflects
+/// genuine structural corruption in an otherwise-recognized MachO object
+/// (a truncated segment/section, or a malformed UUID load command) — not
+/// merely "not a MachO object" (`NoMacho`, silently skipped by callers) and
+/// not an unrelated I/O failure (permission denied, file vanished mid-scan).
+/// The latter is a real error and must fail the scan instead of being
+/// misreported as a broken symbol, which is why it's returned rather than
+/// swallowed. `ErrorKind::InvalidMacho` is produced by `utils::macho_validate`'s
+/// own structural check below, not inferred from `macho::get_uuids_for_*`.
+fn classify_macho_error(path: &Path, err: Error, broken: &Mutex<Vec<BrokenFile>>) -> Result<()> {
+    match *err.kind() {
+        ErrorKind::NoMacho => Ok(()),
+        ErrorKind::InvalidMacho(_) => {
+            broken.lock().unwrap().push(BrokenFile {
+                path: path.display().to_string(),
+                error_string: err.to_string(),
+            });
+            Ok(())
+        }
+        _ => Err(err),
+    }
+}
+
+/// Looks up `path`/`mtime`/`size` in the cache, falling back to parsing
+/// `path` as a MachO file and hashing it on a miss.  Returns `None` both
+/// when the file is not a MachO object at all and when it is a broken one
+/// (the latter is additionally recorded in `ctx.broken`).
+fn checksum_and_uuids_for_path(path: &Path, mtime: i64, size: u64, ctx: ScanCtx)
+    -> Result<Option<(String, HashSet<Uuid>)>>
+{
+    let key = env::current_dir()?.join(path);
+    if let Some(cached) = ctx.cache.lock().unwrap().lookup(&key, mtime, size) {
+        return Ok(Some(cached));
+    }
+    if let Err(err) = macho_validate::validate_path(path, size) {
+        classify_macho_error(path, err, ctx.broken)?;
+        return Ok(None);
+    }
+    let uuids = match macho::get_uuids_for_path(path) {
+        Ok(uuids) => uuids,
+        Err(err) => {
+            classify_macho_error(path, err, ctx.broken)?;
+            return Ok(None);
+        }
+    };
+    let checksum = get_sha1_checksum(&mut fs::File::open(path)?)?;
+    ctx.cache.lock().unwrap().store(&key, mtime, size, checksum.clone(), uuids.clone());
+    Ok(Some((checksum, uuids)))
+}
+
+fn scan_fs_file(path: &Path, base: &Path, ctx: ScanCtx) -> Result<Option<DSymRef>> {
+    let md = fs::metadata(path)?;
+    let mtime = mtime_to_i64(md.modified()?);
+    let (checksum, uuids) = match checksum_and_uuids_for_path(path, mtime, md.len(), ctx)? {
+        Some(pair) => pair,
+        None => return Ok(None),
+    };
+    let name = Path::new("DebugSymbols").join(path.strip_prefix(base).unwrap());
+    Ok(Some(DSymRef {
+        var: DSymVar::FsFile(path.to_path_buf()),
+        arc_name: name.to_string_lossy().into_owned(),
+        cache_key: env::current_dir()?.join(path),
+        mtime: mtime,
+        checksum: checksum,
+        size: md.len(),
+        uuids: uuids,
+        manifest: None,
+    }))
+}
+
+fn scan_zip_entry(zip_path: &Path, idx: usize, ctx: ScanCtx) -> Result<Option<DSymRef>> {
+    let zip_md = fs::metadata(zip_path)?;
+    let mtime = mtime_to_i64(zip_md.modified()?);
+    // Zip entries don't have their own mtime/size on disk, so the cache
+    // key folds the entry index into the path of the archive that holds
+    // it.
+    let cache_key = PathBuf::from(format!("{}!{}", env::current_dir()?.join(zip_path).display(), idx));
+
+    let mut archive = zip::ZipArchive::new(fs::File::open(zip_path)?)?;
+    let cached = ctx.cache.lock().unwrap().lookup(&cache_key, mtime, zip_md.len());
+    let (checksum, uuids) = if let Some(pair) = cached {
+        pair
+    } else {
+        let entry_size = archive.by_index(idx)?.size();
+        {
+            let mut probe = Vec::new();
+            archive.by_index(idx)?.take(macho_validate::MAX_HEADER_PROBE as u64)
+                .read_to_end(&mut probe)?;
+            if let Err(err) = macho_validate::validate_bytes(&probe, entry_size) {
+                classify_macho_error(zip_path, err, ctx.broken)?;
+                return Ok(None);
+            }
+        }
+        let uuids = match macho::get_uuids_for_reader(archive.by_index(idx)?) {
+            Ok(uuids) => uuids,
+            Err(err) => {
+                classify_macho_error(zip_path, err, ctx.broken)?;
+                return Ok(None);
+            }
+        };
+        let checksum = get_sha1_checksum(&mut archive.by_index(idx)?)?;
+        ctx.cache.lock().unwrap().store(&cache_key, mtime, zip_md.len(), checksum.clone(), uuids.clone());
+        (checksum, uuids)
+    };
+
+    let entry = archive.by_index(idx)?;
+    let name = Path::new("DebugSymbols").join(entry.name());
+    Ok(Some(DSymRef {
+        var: DSymVar::ZipFile(zip_path.to_path_buf(), idx),
+        arc_name: name.to_string_lossy().into_owned(),
+        cache_key: cache_key,
+        mtime: mtime,
+        checksum: checksum,
+        size: entry.size(),
+        uuids: uuids,
+        manifest: None,
+    }))
+}
+
+fn scan_task(task: &ScanTask, base: &Path, ctx: ScanCtx) -> Result<Option<DSymRef>> {
+    match *task {
+        ScanTask::FsFile(ref path) => scan_fs_file(path, base, ctx),
+        ScanTask::ZipEntry(ref zip_path, idx) => scan_zip_entry(zip_path, idx, ctx),
+    }
+}
+
 struct BatchIter<'a> {
     path: PathBuf,
     wd_iter: Fuse<WalkDirIter>,
-    open_zip: Rc<RefCell<Option<zip::ZipArchive<fs::File>>>>,
-    open_zip_index: usize,
+    pending_zip: Option<(PathBuf, usize)>,
+    pending_zip_idx: usize,
     uuids: Option<&'a HashSet<Uuid>>,
     allow_zips: bool,
     found_uuids: RefCell<&'a mut HashSet<Uuid>>,
+    ctx: ScanCtx<'a>,
+    // Matches found while filling out a chunk after the current batch was
+    // already full; handed out as the start of the next batch instead of
+    // being dropped on the floor.
+    pending: Vec<DSymRef>,
 }
 
 impl<'a> BatchIter<'a> {
     pub fn new<P: AsRef<Path>>(path: P, uuids: Option<&'a HashSet<Uuid>>,
-                               allow_zips: bool, found_uuids: &'a mut HashSet<Uuid>)
+                               allow_zips: bool, found_uuids: &'a mut HashSet<Uuid>,
+                               cache: &'a Mutex<DSymCache>, broken: &'a Mutex<Vec<BrokenFile>>)
         -> BatchIter<'a>
     {
         BatchIter {
             path: path.as_ref().to_path_buf(),
             wd_iter: WalkDir::new(&path).into_iter().fuse(),
-            open_zip: Rc::new(RefCell::new(None)),
-            open_zip_index: !0,
+            pending_zip: None,
+            pending_zip_idx: 0,
             uuids: uuids,
             allow_zips: allow_zips,
             found_uuids: RefCell::new(found_uuids),
+            ctx: ScanCtx { cache: cache, broken: broken },
+            pending: Vec::new(),
         }
     }
 
@@ -114,123 +386,113 @@ impl<'a> BatchIter<'a> {
         }
         batch.len() >= BATCH_SIZE
     }
+
+    /// Pulls up to `limit` scan tasks off the walk, opening (but not yet
+    /// reading) zip archives just long enough to learn their entry count.
+    fn next_chunk_tasks(&mut self, limit: usize) -> Result<Vec<ScanTask>> {
+        let mut tasks = Vec::with_capacity(limit);
+        while tasks.len() < limit {
+            if let Some((zip_path, len)) = self.pending_zip.clone() {
+                if self.pending_zip_idx < len {
+                    tasks.push(ScanTask::ZipEntry(zip_path, self.pending_zip_idx));
+                    self.pending_zip_idx += 1;
+                    continue;
+                } else {
+                    self.pending_zip = None;
+                }
+            }
+
+            match self.wd_iter.next() {
+                Some(dent_res) => {
+                    let dent = dent_res?;
+                    let md = dent.metadata()?;
+                    if !md.is_file() {
+                        continue;
+                    }
+                    if self.allow_zips && is_zip_file(fs::File::open(dent.path())?) {
+                        let archive = zip::ZipArchive::new(fs::File::open(dent.path())?)?;
+                        self.pending_zip = Some((dent.path().to_path_buf(), archive.len()));
+                        self.pending_zip_idx = 0;
+                    } else {
+                        tasks.push(ScanTask::FsFile(dent.path().to_path_buf()));
+                    }
+                }
+                None => break,
+            }
+        }
+        Ok(tasks)
+    }
 }
 
 impl<'a> Iterator for BatchIter<'a> {
     type Item = Result<Vec<DSymRef>>;
 
     fn next(&mut self) -> Option<Result<Vec<DSymRef>>> {
+        if self.found_all() && self.pending.is_empty() {
+            return None;
+        }
+
         let pb = ProgressBar::new_spinner();
         pb.set_style(ProgressStyle::default_spinner()
             .tick_chars("/|\\- ")
             .template("{spinner} Looking for symbols... {msg:.dim}"));
 
-        let mut batch = vec![];
-
-        macro_rules! uuid_match {
-            ($load:expr) => {
-                match $load {
-                    Ok(uuids) => {
-                        if let Some(ref expected_uuids) = self.uuids {
-                            if !uuids.is_disjoint(expected_uuids) {
-                                Some(uuids)
-                            } else {
-                                None
-                            }
-                        } else if !uuids.is_empty() {
-                            Some(uuids)
-                        } else {
-                            None
-                        }
-                    }
-                    Err(err) => {
-                        if let &ErrorKind::NoMacho = err.kind() {
-                            None
-                        } else {
-                            return Some(Err(err));
-                        }
-                    }
-                }
+        let mut pending = mem::replace(&mut self.pending, Vec::new());
+        let mut batch = Vec::new();
+        batch.append(&mut pending);
+        let mut batch_full = batch.len() >= BATCH_SIZE;
+
+        while !batch_full && !self.found_all() {
+            let tasks = match self.next_chunk_tasks(SCAN_CHUNK_SIZE) {
+                Ok(tasks) => tasks,
+                Err(err) => return Some(Err(err)),
+            };
+            if tasks.is_empty() {
+                break;
             }
-        }
-
-        let mut show_zip_continue = true;
-        while !self.found_all() {
-            if self.open_zip_index == !0 {
-                *self.open_zip.borrow_mut() = None;
+            if let Some(name) = tasks.last().and_then(ScanTask::display_name) {
+                pb.set_message(&name);
             }
 
-            if self.open_zip_index != !0 {
-                let mut archive_ptr = self.open_zip.borrow_mut();
-                let mut archive = archive_ptr.as_mut().unwrap();
-                if show_zip_continue {
-                    show_zip_continue = false;
+            // Hand the whole chunk to the rayon pool at once: each task
+            // reopens whatever file or zip archive it needs, so the
+            // checksum/MachO work for every file in the chunk proceeds
+            // concurrently instead of one file at a time.
+            let base = &self.path;
+            let ctx = self.ctx;
+            let results: Vec<Result<Option<DSymRef>>> = rayon::scope(|s| {
+                let (tx, rx) = sync_channel(tasks.len());
+                for task in &tasks {
+                    let tx = tx.clone();
+                    s.spawn(move |_| {
+                        let _ = tx.send(scan_task(task, base, ctx));
+                    });
                 }
-                if self.open_zip_index >= archive.len() {
-                    self.open_zip_index = !0;
-                    if batch.len() != 0 {
-                        break;
-                    }
-                } else {
-                    if let Some(uuids) = uuid_match!(macho::get_uuids_for_reader(
-                            iter_try!(archive.by_index(self.open_zip_index))))
-                    {
-                        let mut f = iter_try!(archive.by_index(self.open_zip_index));
-                        let name = Path::new("DebugSymbols").join(f.name());
-                        if self.push_ref(&mut batch, DSymRef {
-                            var: DSymVar::ZipFile(self.open_zip.clone(), self.open_zip_index),
-                            arc_name: name.to_string_lossy().into_owned(),
-                            checksum: iter_try!(get_sha1_checksum(&mut f)),
-                            size: f.size(),
-                            uuids: uuids,
-                        }) {
-                            break;
-                        }
-                    }
-                    self.open_zip_index += 1;
+                drop(tx);
+                rx.iter().collect()
+            });
+
+            for result in results {
+                let dsym_ref = match result {
+                    Ok(Some(dsym_ref)) => dsym_ref,
+                    Ok(None) => continue,
+                    Err(err) => return Some(Err(err)),
+                };
+                let matched = self.uuids.map_or(!dsym_ref.uuids.is_empty(),
+                                                 |expected| !dsym_ref.uuids.is_disjoint(expected));
+                if !matched {
+                    continue;
                 }
-            } else if let Some(dent_res) = self.wd_iter.next() {
-                let dent = iter_try!(dent_res);
-                let md = iter_try!(dent.metadata());
-                if md.is_file() {
-                    if let Some(fname) = dent.path().file_name().and_then(|x| x.to_str()) {
-                        pb.set_message(fname);
-                    }
-                    if self.allow_zips && is_zip_file(iter_try!(fs::File::open(&dent.path()))) {
-                        show_zip_continue = false;
-                        let f = iter_try!(fs::File::open(dent.path()));
-                        if let Ok(archive) = zip::ZipArchive::new(f) {
-                            *self.open_zip.borrow_mut() = Some(archive);
-                            self.open_zip_index = 0;
-                            // whenever we switch the zip we need to yield because we
-                            // might have references to an earlier zip
-                            if batch.len() > 0 {
-                                break;
-                            }
-                        }
-                    } else if let Some(uuids) = uuid_match!(macho::get_uuids_for_path(
-                            dent.path())) {
-                        let name = Path::new("DebugSymbols")
-                            .join(dent.path().strip_prefix(&self.path).unwrap());
-                        if self.push_ref(&mut batch, DSymRef {
-                            var: DSymVar::FsFile(dent.path().to_path_buf()),
-                            arc_name: name.to_string_lossy().into_owned(),
-                            checksum: iter_try!(get_sha1_checksum(
-                                &mut iter_try!(fs::File::open(dent.path())))),
-                            size: md.len(),
-                            uuids: uuids,
-                        }) {
-                            break;
-                        }
-                    }
+                let target = if batch_full { &mut pending } else { &mut batch };
+                if self.push_ref(target, dsym_ref) && !batch_full {
+                    batch_full = true;
                 }
-            } else {
-                break;
             }
         }
 
+        self.pending = pending;
         pb.finish_and_clear();
-        if batch.len() == 0 {
+        if batch.is_empty() {
             None
         } else {
             Some(Ok(batch))
@@ -258,15 +520,95 @@ fn find_missing_files(api: &mut Api,
     Ok(rv)
 }
 
-fn zip_up_missing(refs: &[DSymRef]) -> Result<TempFile> {
+/// Computes (or returns the cached) chunk manifest for a ref's underlying
+/// file, for refs large enough that per-chunk deduplication is worth the
+/// overhead of chunking in the first place.  Returns `None` for smaller
+/// files, which are uploaded whole.
+fn chunks_for_ref(r: &DSymRef, ctx: ScanCtx) -> Result<Option<Vec<Chunk>>> {
+    if r.size < chunking::MIN_CHUNKABLE_SIZE {
+        return Ok(None);
+    }
+    if let Some(cached) = ctx.cache.lock().unwrap().lookup_chunks(&r.cache_key, r.mtime, r.size) {
+        return Ok(Some(cached));
+    }
+    let chunks = match r.var {
+        DSymVar::FsFile(ref p) => chunking::chunk_reader(&mut File::open(p)?)?,
+        DSymVar::ZipFile(ref zip_path, idx) => {
+            let mut archive = zip::ZipArchive::new(fs::File::open(zip_path)?)?;
+            chunking::chunk_reader(&mut archive.by_index(idx)?)?
+        }
+    };
+    ctx.cache.lock().unwrap().store_chunks(&r.cache_key, r.mtime, r.size, chunks.clone());
+    Ok(Some(chunks))
+}
+
+/// Asks the server which of the given chunk digests it doesn't already
+/// have, so only genuinely new chunks need to be uploaded. Callers must
+/// treat an `Err` here as "chunked uploads aren't supported" and fall back
+/// to uploading affected files whole, since an older server has no way to
+/// reassemble a manifest plus a partial set of chunks.
+fn find_missing_chunks(api: &mut Api, org: &str, project: &str, digests: &[String])
+    -> Result<HashSet<String>>
+{
+    if digests.is_empty() {
+        return Ok(HashSet::new());
+    }
+    debug!("Checking for missing debug symbol chunks: {} candidates", digests.len());
+    let refs: Vec<_> = digests.iter().map(|x| x.as_str()).collect();
+    let missing = api.find_missing_dsym_chunks(org, project, &refs)?;
+    debug!("Missing debug symbol chunks: {:#?}", &missing);
+    Ok(missing.into_iter().collect())
+}
+
+/// Picks the compression method and level for the upload archive from the
+/// `--compression`/`--compression-level` flags.
+///
+/// Defaults to bzip2, which compresses dSYM DWARF sections considerably
+/// tighter than deflate, trading some CPU time for bandwidth on the kind of
+/// slow CI link this flag exists for. `zstd` isn't offered: this crate's zip
+/// dependency doesn't support it at the pinned version. Any other unknown
+/// or unsupported value falls back to deflate, the one codec guaranteed to
+/// be available, so a typo'd `--compression` value degrades gracefully
+/// instead of failing the upload outright.
+fn archive_options(matches: &ArgMatches) -> zip::write::FileOptions {
+    let method = match matches.value_of("compression").unwrap_or("bzip2") {
+        "stored" => zip::CompressionMethod::Stored,
+        "deflate" => zip::CompressionMethod::Deflated,
+        "bzip2" => zip::CompressionMethod::Bzip2,
+        other => {
+            warn!("Unknown or unsupported compression method '{}', falling back to deflate", other);
+            zip::CompressionMethod::Deflated
+        }
+    };
+    let mut options = zip::write::FileOptions::default().compression_method(method);
+    if let Some(level) = matches.value_of("compression_level") {
+        if let Ok(level) = level.parse::<i32>() {
+            options = options.compression_level(Some(level));
+        }
+    }
+    options
+}
+
+fn zip_up_missing(refs: &[DSymRef], options: zip::write::FileOptions,
+                  missing_chunks: &HashSet<String>) -> Result<TempFile> {
     println!("{} Compressing {} missing debug symbol files", style("[2/3]").dim(),
              style(refs.len()).yellow());
     let total_bytes = refs.iter().map(|x| x.size).sum();
     let pb = make_byte_progress_bar(total_bytes);
     let tf = TempFile::new()?;
     let mut zip = zip::ZipWriter::new(tf.open());
+    // Shared across every ref below so a chunk digest common to two refs in
+    // this batch (or repeated within one ref's own manifest) is only ever
+    // written to the archive once.
+    let mut written_chunks = HashSet::new();
     for ref r in refs {
-        r.add_to_archive(&mut zip, &pb)?;
+        match r.manifest {
+            Some(ref manifest) => {
+                r.add_chunks_to_archive(&mut zip, &pb, options, manifest, missing_chunks,
+                                        &mut written_chunks)?;
+            }
+            None => r.add_to_archive(&mut zip, &pb, options)?,
+        }
     }
     pb.finish_and_clear();
     Ok(tf)
@@ -275,9 +617,11 @@ fn zip_up_missing(refs: &[DSymRef]) -> Result<TempFile> {
 fn upload_dsyms(api: &mut Api,
                 refs: &[DSymRef],
                 org: &str,
-                project: &str)
+                project: &str,
+                options: zip::write::FileOptions,
+                missing_chunks: &HashSet<String>)
                 -> Result<Vec<DSymFile>> {
-    let tf = zip_up_missing(refs)?;
+    let tf = zip_up_missing(refs, options, missing_chunks)?;
     println!("{} Uploading debug symbol files", style("[3/3]").dim());
     Ok(api.upload_dsyms(org, project, tf.path())?)
 }
@@ -341,6 +685,23 @@ pub fn make_app<'a, 'b: 'a>(app: App<'a, 'b>) -> App<'a, 'b> {
                     a dialog is shown.  If this parameter is passed Xcode will wait \
                     for the process to finish before the build finishes and output \
                     will be shown in the Xcode build output."))
+        .arg(Arg::with_name("fail_on_broken")
+             .long("fail-on-broken")
+             .help("Fails the command if a broken or truncated debug symbol file \
+                    is encountered while scanning (for instance from an \
+                    interrupted build)."))
+        .arg(Arg::with_name("compression")
+             .long("compression")
+             .value_name("METHOD")
+             .help("The compression method to use for the upload archive: \
+                    stored, deflate or bzip2 (zstd is not available in this \
+                    build) [default: bzip2]. An unrecognized value falls \
+                    back to deflate."))
+        .arg(Arg::with_name("compression_level")
+             .long("compression-level")
+             .value_name("LEVEL")
+             .help("The compression level to pass to the chosen --compression \
+                    method."))
 }
 
 pub fn execute<'a>(matches: &ArgMatches<'a>, config: &Config) -> Result<()> {
@@ -361,6 +722,8 @@ pub fn execute<'a>(matches: &ArgMatches<'a>, config: &Config) -> Result<()> {
         uuids.map(|s| Uuid::parse_str(s).unwrap()).collect::<HashSet<_>>()
     });
     let mut found_uuids: HashSet<Uuid> = HashSet::new();
+    let cache = Mutex::new(DSymCache::load(config)?);
+    let broken_files: Mutex<Vec<BrokenFile>> = Mutex::new(Vec::new());
     let info_plist = match matches.value_of("info_plist") {
         Some(path) => Some(xcode::InfoPlist::from_path(path)?),
         None => xcode::InfoPlist::discover_from_env()?,
@@ -373,8 +736,9 @@ pub fn execute<'a>(matches: &ArgMatches<'a>, config: &Config) -> Result<()> {
     let (org, project) = config.get_org_and_project(matches)?;
     let mut api = Api::new(config);
     let mut total_uploaded = 0;
+    let archive_opts = archive_options(matches);
 
-    xcode::MayDetach::wrap("Debug symbol upload", |md| {
+    let result = xcode::MayDetach::wrap("Debug symbol upload", |md| {
         // Optionally detach if run from xcode
         if !matches.is_present("force_foreground") {
             md.may_detach()?;
@@ -385,7 +749,7 @@ pub fn execute<'a>(matches: &ArgMatches<'a>, config: &Config) -> Result<()> {
         for path in paths.into_iter() {
             debug!("Scanning {}", path.display());
             for batch_res in BatchIter::new(path, find_uuids.as_ref(), zips,
-                                            &mut found_uuids) {
+                                            &mut found_uuids, &cache, &broken_files) {
                 if batch_num > 0 {
                     println!("");
                 }
@@ -397,14 +761,43 @@ pub fn execute<'a>(matches: &ArgMatches<'a>, config: &Config) -> Result<()> {
                 }
                 println!("{} Found {} debug symbol files. Checking for missing symbols on server",
                          style("[1/3]").dim(), style(batch.len()).yellow());
-                let missing = find_missing_files(&mut api, batch, &org, &project)?;
+                let mut missing = find_missing_files(&mut api, batch, &org, &project)?;
                 if missing.len() == 0 {
                     println!("{} Nothing to compress, all symbols are on the server",
                              style("[2/3]").dim());
                     println!("{} Nothing to upload", style("[3/3]").dim());
                     continue;
                 }
-                let rv = upload_dsyms(&mut api, &missing, &org, &project)?;
+
+                let scan_ctx = ScanCtx { cache: &cache, broken: &broken_files };
+                let mut chunk_digests = HashSet::new();
+                for r in missing.iter_mut() {
+                    r.manifest = chunks_for_ref(r, scan_ctx)?;
+                    if let Some(ref manifest) = r.manifest {
+                        for chunk in manifest {
+                            chunk_digests.insert(chunk.checksum.clone());
+                        }
+                    }
+                }
+                let chunk_digests: Vec<_> = chunk_digests.into_iter().collect();
+                let missing_chunks = match find_missing_chunks(&mut api, &org, &project,
+                                                               &chunk_digests) {
+                    Ok(missing_chunks) => missing_chunks,
+                    Err(err) => {
+                        // The server doesn't support (or rejected) the chunk
+                        // dedup endpoint. Fall back to uploading every
+                        // missing file whole rather than uploading manifests
+                        // it has no way to reassemble.
+                        debug!("Chunked upload unavailable, uploading whole files: {}", err);
+                        for r in missing.iter_mut() {
+                            r.manifest = None;
+                        }
+                        HashSet::new()
+                    }
+                };
+
+                let rv = upload_dsyms(&mut api, &missing, &org, &project, archive_opts,
+                                      &missing_chunks)?;
                 if rv.len() > 0 {
                     total_uploaded += rv.len();
                     println!("Newly uploaded debug symbols:");
@@ -465,5 +858,29 @@ pub fn execute<'a>(matches: &ArgMatches<'a>, config: &Config) -> Result<()> {
         }
 
         Ok(())
-    })
+    });
+
+    // The cache is flushed regardless of whether the upload succeeded so
+    // that symbols we already scanned this run are not rescanned next time.
+    cache.lock().unwrap().flush(config)?;
+
+    let broken = broken_files.into_inner().unwrap();
+    if !broken.is_empty() {
+        println!("");
+        println_stderr!("{}", style(format!("Warning: found {} broken debug symbol file(s):",
+                                             broken.len())).yellow());
+        for bf in &broken {
+            println_stderr!("  {} ({})", bf.path, bf.error_string);
+        }
+    }
+
+    if result.is_err() {
+        return result;
+    }
+
+    if matches.is_present("fail_on_broken") && !broken.is_empty() {
+        return Err(ErrorKind::QuietExit(1).into());
+    }
+
+    result
 }
@@ -0,0 +1,192 @@
+//! Content-defined chunking for deduplicating large, incrementally changing
+//! debug symbol files across uploads.
+//!
+//! Rather than splitting a file at fixed offsets, chunk boundaries are
+//! picked wherever a rolling hash over the last `WINDOW_SIZE` bytes happens
+//! to land on a multiple of the target chunk size. That way inserting or
+//! removing a few bytes only reshuffles the chunks around the edit instead
+//! of shifting every boundary after it, so a dSYM that changes by a few
+//! functions between builds still shares most of its chunks with the
+//! previous upload.
+
+use std::io::Read;
+
+use prelude::*;
+use utils::get_sha1_checksum;
+
+/// Width of the rolling hash window, in bytes.
+const WINDOW_SIZE: usize = 64;
+
+/// Chunks other than a file's last one are never produced smaller than
+/// this, to keep the manifest from exploding on pathological (e.g. highly
+/// repetitive) input. The last chunk holds whatever remains and may be
+/// shorter.
+const MIN_CHUNK_SIZE: usize = 512 * 1024;
+
+/// Chunks are forced to end at this size even if the rolling hash never
+/// lands on a boundary, bounding the worst case manifest and memory use.
+const MAX_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Target average chunk size the boundary mask is tuned for.
+const TARGET_CHUNK_SIZE: u64 = 2 * 1024 * 1024;
+
+/// Files smaller than this are uploaded whole; chunking them would only add
+/// overhead without meaningfully reducing bandwidth on a change.
+pub const MIN_CHUNKABLE_SIZE: u64 = MAX_CHUNK_SIZE as u64;
+
+// Reused as the rolling hash multiplier. Any odd constant works; this is
+// the FNV-1a prime, which happens to mix bits well for this purpose too.
+const MUL: u64 = 1_099_511_628_211;
+
+fn boundary_mask() -> u64 {
+    TARGET_CHUNK_SIZE.next_power_of_two() - 1
+}
+
+/// One content-addressed slice of a chunked file.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Chunk {
+    pub checksum: String,
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// A polynomial rolling hash over a fixed-size sliding window, used to pick
+/// content-defined chunk boundaries.
+struct RollingHash {
+    window: [u8; WINDOW_SIZE],
+    pos: usize,
+    hash: u64,
+    // MUL^WINDOW_SIZE, precomputed once so the oldest byte's contribution
+    // can be undone in constant time.
+    mul_pow: u64,
+}
+
+impl RollingHash {
+    fn new() -> RollingHash {
+        let mut mul_pow = 1u64;
+        for _ in 0..WINDOW_SIZE {
+            mul_pow = mul_pow.wrapping_mul(MUL);
+        }
+        RollingHash {
+            window: [0; WINDOW_SIZE],
+            pos: 0,
+            hash: 0,
+            mul_pow: mul_pow,
+        }
+    }
+
+    fn roll(&mut self, byte: u8) -> u64 {
+        let outgoing = self.window[self.pos];
+        self.hash = self.hash.wrapping_mul(MUL).wrapping_add(byte as u64);
+        self.hash = self.hash.wrapping_sub((outgoing as u64).wrapping_mul(self.mul_pow));
+        self.window[self.pos] = byte;
+        self.pos = (self.pos + 1) % WINDOW_SIZE;
+        self.hash
+    }
+}
+
+/// Splits a reader's contents into content-defined chunks and returns their
+/// offsets, lengths and per-chunk SHA-1 checksums, in order.
+pub fn chunk_reader<R: Read>(reader: &mut R) -> Result<Vec<Chunk>> {
+    let mask = boundary_mask();
+    let mut hasher = RollingHash::new();
+    let mut chunks = Vec::new();
+    let mut buf: Vec<u8> = Vec::new();
+    let mut offset: u64 = 0;
+    let mut read_buf = [0u8; 8192];
+
+    loop {
+        let n = reader.read(&mut read_buf)?;
+        if n == 0 {
+            break;
+        }
+        for &byte in &read_buf[..n] {
+            buf.push(byte);
+            let hash = hasher.roll(byte);
+            let at_boundary = buf.len() >= MAX_CHUNK_SIZE ||
+                (buf.len() >= MIN_CHUNK_SIZE && hash & mask == 0);
+            if at_boundary {
+                offset += finish_chunk(&mut buf, offset, &mut chunks)? as u64;
+            }
+        }
+    }
+    if !buf.is_empty() {
+        finish_chunk(&mut buf, offset, &mut chunks)?;
+    }
+
+    Ok(chunks)
+}
+
+fn finish_chunk(buf: &mut Vec<u8>, offset: u64, chunks: &mut Vec<Chunk>) -> Result<usize> {
+    let len = buf.len();
+    let checksum = get_sha1_checksum(&mut &buf[..])?;
+    chunks.push(Chunk {
+        checksum: checksum,
+        offset: offset,
+        len: len as u64,
+    });
+    buf.clear();
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    // Deterministic, dependency-free pseudo-random bytes (xorshift32) so
+    // these tests don't need to pull in an external rand crate.
+    fn pseudo_random_bytes(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed | 1;
+        let mut out = Vec::with_capacity(len);
+        for _ in 0..len {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            out.push((state & 0xff) as u8);
+        }
+        out
+    }
+
+    #[test]
+    fn test_chunks_are_contiguous_and_reassemble() {
+        let data = pseudo_random_bytes(4 * 1024 * 1024, 1);
+        let chunks = chunk_reader(&mut &data[..]).unwrap();
+
+        assert!(chunks.len() > 1, "expected more than one chunk for 4MiB of input");
+
+        let mut expected_offset = 0u64;
+        for chunk in &chunks {
+            assert_eq!(chunk.offset, expected_offset);
+            let slice = &data[chunk.offset as usize..(chunk.offset + chunk.len) as usize];
+            let checksum = get_sha1_checksum(&mut &slice[..]).unwrap();
+            assert_eq!(chunk.checksum, checksum);
+            expected_offset += chunk.len;
+        }
+        assert_eq!(expected_offset, data.len() as u64);
+    }
+
+    #[test]
+    fn test_boundaries_are_stable_across_an_unrelated_edit() {
+        let mut data = pseudo_random_bytes(10 * 1024 * 1024, 2);
+        let before = chunk_reader(&mut &data[..]).unwrap();
+
+        // Insert a few bytes well away from either end, simulating a small
+        // code change in an otherwise unchanged file.
+        let edit_at = data.len() / 2;
+        for (i, byte) in pseudo_random_bytes(37, 3).into_iter().enumerate() {
+            data.insert(edit_at + i, byte);
+        }
+        let after = chunk_reader(&mut &data[..]).unwrap();
+
+        let before_checksums: HashSet<_> = before.iter().map(|c| c.checksum.clone()).collect();
+        let shared = after.iter().filter(|c| before_checksums.contains(&c.checksum)).count();
+
+        // Content-defined chunking should re-find most of the same chunk
+        // boundaries on either side of a small, localized edit; only the
+        // chunk(s) touching the edit itself should differ.
+        assert!(shared >= before.len().saturating_sub(2),
+                "expected most chunks to survive a small edit: {} of {} matched",
+                shared, before.len());
+    }
+}
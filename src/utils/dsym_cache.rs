@@ -0,0 +1,285 @@
+//! A persistent, on-disk cache of previously scanned symbol metadata.
+//!
+//! Walking a large DerivedData tree and recomputing a SHA-1 checksum plus
+//! the MachO UUID list for every candidate file on every invocation is the
+//! dominant cost of `upload-dsym` once nothing has actually changed between
+//! runs.  This cache is keyed by a file's absolute path, modification time
+//! and size; when those three are unchanged we trust the previously
+//! recorded checksum and UUID set and skip reading the file entirely.
+//!
+//! This mirrors the broken-files cache: a serialized map loaded once at the
+//! start of `execute` and flushed back to disk at the end.
+//!
+//! Entries may also carry a content-defined chunk manifest (see
+//! `utils::chunking`), populated the first time a file is actually uploaded
+//! so that a later upload of the same unchanged file can skip rechunking it.
+//!
+//! Entries are keyed by path/mtime/size, so a file that's rebuilt leaves
+//! its old entry behind under the old key forever. `flush` prunes any
+//! entry that either wasn't looked up or stored during the current run, or
+//! whose underlying file no longer exists, so the cache file doesn't grow
+//! without bound across repeated runs.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json;
+use uuid::Uuid;
+
+use config::Config;
+use prelude::*;
+use utils::chunking::Chunk;
+
+const CACHE_FILE_NAME: &'static str = "dsym_scan_cache.json";
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct CacheKey {
+    path: PathBuf,
+    mtime: i64,
+    size: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    checksum: String,
+    uuids: HashSet<Uuid>,
+    // Chunk manifests are populated lazily, only once a file is actually
+    // uploaded, so older cache entries (and ones for files that never ended
+    // up missing on the server) won't have one yet.
+    #[serde(default)]
+    chunks: Option<Vec<Chunk>>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CacheData {
+    entries: Vec<(CacheKey, CacheEntry)>,
+}
+
+/// Converts a file modification time into the form stored in the cache.
+pub fn mtime_to_i64(mtime: SystemTime) -> i64 {
+    match mtime.duration_since(UNIX_EPOCH) {
+        Ok(dur) => dur.as_secs() as i64,
+        Err(_) => 0,
+    }
+}
+
+/// In-memory view of the persisted dsym scan cache.
+///
+/// Load once with `DSymCache::load`, consult it with `lookup` while
+/// scanning, record new results with `store`, and call `flush` once at the
+/// end of the command to persist anything that changed.
+#[derive(Debug, Default)]
+pub struct DSymCache {
+    entries: HashMap<CacheKey, CacheEntry>,
+    // Keys looked up (and hit) or stored during the current run, so `flush`
+    // can tell a legitimately-unchanged entry apart from one left behind by
+    // a file that no longer exists.
+    touched: HashSet<CacheKey>,
+    dirty: bool,
+}
+
+impl DSymCache {
+    /// Loads the cache from the config's cache directory.  A missing or
+    /// unreadable cache file is treated as an empty cache rather than an
+    /// error, since the cache is purely an optimization.
+    pub fn load(config: &Config) -> Result<DSymCache> {
+        let filename = cache_filename(config)?;
+        match load_cache_from_file(&filename) {
+            Ok(entries) => Ok(DSymCache {
+                entries: entries,
+                touched: HashSet::new(),
+                dirty: false,
+            }),
+            Err(_) => Ok(DSymCache::default()),
+        }
+    }
+
+    /// Looks up a previously cached checksum and UUID set for a file whose
+    /// modification time and size match exactly.
+    pub fn lookup(&mut self, path: &Path, mtime: i64, size: u64) -> Option<(String, HashSet<Uuid>)> {
+        let key = CacheKey {
+            path: path.to_path_buf(),
+            mtime: mtime,
+            size: size,
+        };
+        let hit = self.entries.get(&key).map(|entry| (entry.checksum.clone(), entry.uuids.clone()));
+        if hit.is_some() {
+            self.touched.insert(key);
+        }
+        hit
+    }
+
+    /// Records a freshly computed checksum and UUID set for a file.
+    pub fn store(&mut self, path: &Path, mtime: i64, size: u64, checksum: String, uuids: HashSet<Uuid>) {
+        let key = CacheKey {
+            path: path.to_path_buf(),
+            mtime: mtime,
+            size: size,
+        };
+        // Preserve a chunk manifest computed for this key in an earlier
+        // call, since `store` is only ever called again for the same key
+        // if the checksum was somehow recomputed despite mtime/size being
+        // unchanged.
+        let chunks = self.entries.get(&key).and_then(|entry| entry.chunks.clone());
+        self.entries.insert(key.clone(), CacheEntry {
+            checksum: checksum,
+            uuids: uuids,
+            chunks: chunks,
+        });
+        self.touched.insert(key);
+        self.dirty = true;
+    }
+
+    /// Looks up a previously computed chunk manifest for a file whose
+    /// modification time and size match exactly.  Returns `None` both when
+    /// the file itself isn't cached yet and when it's cached but hasn't
+    /// been chunked before.
+    pub fn lookup_chunks(&mut self, path: &Path, mtime: i64, size: u64) -> Option<Vec<Chunk>> {
+        let key = CacheKey {
+            path: path.to_path_buf(),
+            mtime: mtime,
+            size: size,
+        };
+        let hit = self.entries.get(&key).and_then(|entry| entry.chunks.clone());
+        if hit.is_some() {
+            self.touched.insert(key);
+        }
+        hit
+    }
+
+    /// Records a freshly computed chunk manifest for a file that's already
+    /// present in the cache.  A no-op if the file itself hasn't been
+    /// scanned yet, since a cache entry always needs a checksum and UUID
+    /// set to exist in the first place.
+    pub fn store_chunks(&mut self, path: &Path, mtime: i64, size: u64, chunks: Vec<Chunk>) {
+        let key = CacheKey {
+            path: path.to_path_buf(),
+            mtime: mtime,
+            size: size,
+        };
+        if let Some(entry) = self.entries.get_mut(&key) {
+            entry.chunks = Some(chunks);
+            self.touched.insert(key);
+            self.dirty = true;
+        }
+    }
+
+    /// Persists the cache to disk, pruning any entry that wasn't touched
+    /// during this run or whose underlying file no longer exists.  Writes
+    /// nothing if nothing changed and nothing needed pruning.
+    pub fn flush(&self, config: &Config) -> Result<()> {
+        let pruned: HashMap<_, _> = self.entries.iter()
+            .filter(|&(key, _)| self.touched.contains(key) && underlying_path(&key.path).exists())
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        if !self.dirty && pruned.len() == self.entries.len() {
+            return Ok(());
+        }
+        save_cache_to_file(&cache_filename(config)?, &pruned)
+    }
+}
+
+/// Zip entry cache keys fold the entry index into the path as
+/// `"<zip path>!<index>"`, which isn't itself a real filesystem path.
+/// Recovers the zip file's own path so staleness can still be checked
+/// against disk for those entries.
+fn underlying_path(path: &Path) -> &Path {
+    path.to_str()
+        .and_then(|s| s.rfind('!').map(|idx| Path::new(&s[..idx])))
+        .unwrap_or(path)
+}
+
+fn cache_filename(config: &Config) -> Result<PathBuf> {
+    Ok(config.get_cache_dir()?.join(CACHE_FILE_NAME))
+}
+
+fn load_cache_from_file(path: &Path) -> Result<HashMap<CacheKey, CacheEntry>> {
+    let f = fs::File::open(path)?;
+    let data: CacheData = serde_json::from_reader(f)?;
+    Ok(data.entries.into_iter().collect())
+}
+
+fn save_cache_to_file(path: &Path, entries: &HashMap<CacheKey, CacheEntry>) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let data = CacheData {
+        entries: entries.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+    };
+    let f = fs::File::create(path)?;
+    serde_json::to_writer(BufWriter::new(f), &data)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_mtime_to_i64() {
+        assert_eq!(mtime_to_i64(UNIX_EPOCH + Duration::from_secs(1000)), 1000);
+    }
+
+    #[test]
+    fn test_lookup_store_round_trip() {
+        let mut cache = DSymCache::default();
+        let path = Path::new("/tmp/example.dSYM/DWARF/example");
+        assert!(cache.lookup(path, 100, 42).is_none());
+
+        let mut uuids = HashSet::new();
+        uuids.insert(Uuid::nil());
+        cache.store(path, 100, 42, "deadbeef".to_string(), uuids.clone());
+
+        let (checksum, cached_uuids) = cache.lookup(path, 100, 42).unwrap();
+        assert_eq!(checksum, "deadbeef");
+        assert_eq!(cached_uuids, uuids);
+
+        // A different mtime or size is a cache miss, even for the same path.
+        assert!(cache.lookup(path, 101, 42).is_none());
+        assert!(cache.lookup(path, 100, 43).is_none());
+    }
+
+    #[test]
+    fn test_store_chunks_is_noop_without_an_existing_entry() {
+        let mut cache = DSymCache::default();
+        let path = Path::new("/tmp/example.dSYM/DWARF/example");
+        cache.store_chunks(path, 100, 42, vec![]);
+        assert!(cache.lookup_chunks(path, 100, 42).is_none());
+    }
+
+    #[test]
+    fn test_lookup_store_chunks_round_trip() {
+        let mut cache = DSymCache::default();
+        let path = Path::new("/tmp/example.dSYM/DWARF/example");
+        cache.store(path, 100, 42, "deadbeef".to_string(), HashSet::new());
+        assert!(cache.lookup_chunks(path, 100, 42).is_none());
+
+        let chunks = vec![Chunk { checksum: "abc".into(), offset: 0, len: 42 }];
+        cache.store_chunks(path, 100, 42, chunks.clone());
+        assert_eq!(cache.lookup_chunks(path, 100, 42), Some(chunks));
+    }
+
+    #[test]
+    fn test_serde_round_trip() {
+        let entry = CacheEntry {
+            checksum: "deadbeef".to_string(),
+            uuids: HashSet::new(),
+            chunks: Some(vec![Chunk { checksum: "abc".into(), offset: 0, len: 10 }]),
+        };
+        let data = CacheData {
+            entries: vec![(CacheKey { path: PathBuf::from("/a"), mtime: 1, size: 2 }, entry.clone())],
+        };
+
+        let json = serde_json::to_string(&data).unwrap();
+        let round_tripped: CacheData = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(round_tripped.entries.len(), 1);
+        assert_eq!(round_tripped.entries[0].0.mtime, 1);
+        assert_eq!(round_tripped.entries[0].1.checksum, entry.checksum);
+        assert_eq!(round_tripped.entries[0].1.chunks, entry.chunks);
+    }
+}
@@ -0,0 +1,198 @@
+//! Structural validation for Mach-O object files, independent of whatever
+//! error granularity `macho::get_uuids_for_path`/`get_uuids_for_reader`
+//! happen to return.
+//!
+//! `--fail-on-broken` needs to tell a file that merely isn't a Mach-O
+//! object apart from one that looks like a Mach-O object but is truncated
+//! or otherwise corrupt -- typically the result of an interrupted build.
+//! This module makes that distinction itself, by reading just the header
+//! and load commands (never the whole, possibly hundreds-of-megabytes,
+//! file) and checking that every segment/section file range it finds
+//! actually fits inside the file, and that an `LC_UUID` load command (if
+//! present) has the size a 16-byte UUID requires.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use prelude::*;
+
+/// Generous upper bound on how much of a Mach-O object's header and load
+/// commands this module will read into memory to validate it. Real load
+/// command areas are a few kilobytes; anything claiming to need more than
+/// this is already corrupt or not worth chasing further.
+pub const MAX_HEADER_PROBE: usize = 1024 * 1024;
+
+const MH_MAGIC: u32 = 0xfeed_face;
+const MH_CIGAM: u32 = 0xcefa_edfe;
+const MH_MAGIC_64: u32 = 0xfeed_facf;
+const MH_CIGAM_64: u32 = 0xcffa_edfe;
+const FAT_MAGIC: u32 = 0xcafe_babe;
+const FAT_CIGAM: u32 = 0xbeba_feca;
+
+const LC_SEGMENT: u32 = 0x1;
+const LC_SEGMENT_64: u32 = 0x19;
+const LC_UUID: u32 = 0x1b;
+
+/// Reads and structurally validates the Mach-O (or fat) object at `path`,
+/// which is known to be `total_size` bytes long on disk.
+///
+/// Returns `Ok(())` both for a structurally sound object and for anything
+/// that doesn't look like a Mach-O object at all -- telling those two
+/// apart is `macho::get_uuids_for_path`'s job, not this function's. Returns
+/// `Err(ErrorKind::InvalidMacho(_))` only for a recognized Mach-O (or fat)
+/// header whose fat slices or segment/section ranges point outside the
+/// file, or whose load commands have an impossible size (under 8 bytes, or
+/// an `LC_UUID` that isn't 24 bytes).
+pub fn validate_path(path: &Path, total_size: u64) -> Result<()> {
+    let mut f = File::open(path)?;
+    let mut probe = vec![0u8; MAX_HEADER_PROBE.min(total_size as usize)];
+    f.read_exact(&mut probe)?;
+    validate_bytes(&probe, total_size)
+}
+
+/// Same as `validate_path`, but against an already-read prefix of the
+/// object (e.g. the start of a zip entry, buffered up to
+/// `MAX_HEADER_PROBE` by the caller).
+pub fn validate_bytes(probe: &[u8], total_size: u64) -> Result<()> {
+    if probe.len() < 4 {
+        return Ok(());
+    }
+    match read_u32(probe, 0, false) {
+        Some(magic) if magic == FAT_MAGIC || magic == FAT_CIGAM => {
+            validate_fat(probe, total_size, magic == FAT_CIGAM)
+        }
+        Some(magic) if is_macho_magic(magic) => {
+            let is64 = magic == MH_MAGIC_64 || magic == MH_CIGAM_64;
+            let swap = magic == MH_CIGAM || magic == MH_CIGAM_64;
+            validate_macho(probe, 0, total_size, is64, swap)
+        }
+        _ => Ok(()),
+    }
+}
+
+fn is_macho_magic(magic: u32) -> bool {
+    magic == MH_MAGIC || magic == MH_CIGAM || magic == MH_MAGIC_64 || magic == MH_CIGAM_64
+}
+
+fn read_u32(data: &[u8], off: usize, swap: bool) -> Option<u32> {
+    let b = data.get(off..off + 4)?;
+    Some(if swap {
+        ((b[0] as u32) << 24) | ((b[1] as u32) << 16) | ((b[2] as u32) << 8) | (b[3] as u32)
+    } else {
+        ((b[3] as u32) << 24) | ((b[2] as u32) << 16) | ((b[1] as u32) << 8) | (b[0] as u32)
+    })
+}
+
+fn read_u64(data: &[u8], off: usize, swap: bool) -> Option<u64> {
+    let (hi_off, lo_off) = if swap { (off, off + 4) } else { (off + 4, off) };
+    let hi = read_u32(data, hi_off, swap)? as u64;
+    let lo = read_u32(data, lo_off, swap)? as u64;
+    Some((hi << 32) | lo)
+}
+
+fn broken(reason: String) -> Error {
+    ErrorKind::InvalidMacho(reason).into()
+}
+
+fn validate_fat(data: &[u8], total_size: u64, swap: bool) -> Result<()> {
+    let nfat_arch = match read_u32(data, 4, swap) {
+        Some(n) => n,
+        // The probe didn't even cover the fat header; inconclusive rather
+        // than broken.
+        None => return Ok(()),
+    };
+    let mut off = 8;
+    for _ in 0..nfat_arch {
+        let (offset, size) = match (read_u32(data, off + 8, swap), read_u32(data, off + 12, swap)) {
+            (Some(offset), Some(size)) => (offset as u64, size as u64),
+            _ => return Ok(()),
+        };
+        if offset.checked_add(size).map_or(true, |end| end > total_size) {
+            return Err(broken(format!(
+                "fat_arch slice at {}+{} runs past the end of the file ({} bytes)",
+                offset, size, total_size)));
+        }
+        off += 20;
+    }
+    Ok(())
+}
+
+fn validate_macho(data: &[u8], base: usize, total_size: u64, is64: bool, swap: bool) -> Result<()> {
+    let ncmds = match read_u32(data, base + 16, swap) {
+        Some(n) => n,
+        None => return Ok(()),
+    };
+    let header_size = if is64 { 32 } else { 28 };
+    let mut off = base + header_size;
+    for _ in 0..ncmds {
+        let cmd = match read_u32(data, off, swap) {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        let cmdsize = match read_u32(data, off + 4, swap) {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        if cmdsize < 8 {
+            return Err(broken(format!(
+                "load command at offset {} has an impossible size {}", off, cmdsize)));
+        }
+        if cmd == LC_UUID && cmdsize != 24 {
+            return Err(broken(format!(
+                "LC_UUID load command has size {} instead of 24", cmdsize)));
+        }
+        if cmd == LC_SEGMENT || cmd == LC_SEGMENT_64 {
+            validate_segment(data, off, total_size, is64, swap)?;
+        }
+        off += cmdsize as usize;
+        if off > data.len() {
+            // Ran past what was probed; anything further can't be checked
+            // without reading more of the file, so stop rather than guess.
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+fn validate_segment(data: &[u8], cmd_off: usize, total_size: u64, is64: bool, swap: bool) -> Result<()> {
+    let (fileoff, filesize, seg_size) = if is64 {
+        (read_u64(data, cmd_off + 40, swap), read_u64(data, cmd_off + 48, swap), 72)
+    } else {
+        (read_u32(data, cmd_off + 32, swap).map(|v| v as u64),
+         read_u32(data, cmd_off + 36, swap).map(|v| v as u64), 56)
+    };
+    let (fileoff, filesize) = match (fileoff, filesize) {
+        (Some(o), Some(s)) => (o, s),
+        _ => return Ok(()),
+    };
+    if fileoff.checked_add(filesize).map_or(true, |end| end > total_size) {
+        return Err(broken(format!(
+            "segment file range {}+{} runs past the end of the file ({} bytes)",
+            fileoff, filesize, total_size)));
+    }
+
+    // `nsects` is always the second-to-last field of both segment_command
+    // variants, immediately before `flags`.
+    let nsects = read_u32(data, cmd_off + seg_size - 8, swap).unwrap_or(0);
+    let sects_off = cmd_off + seg_size;
+    let (section_size, name_size) = if is64 { (80, 32) } else { (68, 32) };
+    for i in 0..nsects {
+        let sect_off = sects_off + (i as usize) * section_size;
+        let (offset, size) = if is64 {
+            (read_u32(data, sect_off + name_size + 16, swap),
+             read_u64(data, sect_off + name_size + 8, swap))
+        } else {
+            (read_u32(data, sect_off + name_size + 8, swap),
+             read_u32(data, sect_off + name_size + 4, swap).map(|v| v as u64))
+        };
+        if let (Some(offset), Some(size)) = (offset, size) {
+            if size > 0 && (offset as u64).checked_add(size).map_or(true, |end| end > total_size) {
+                return Err(broken(format!(
+                    "section file range {}+{} runs past the end of the file ({} bytes)",
+                    offset, size, total_size)));
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,3 @@
+pub mod dsym_cache;
+pub mod chunking;
+pub mod macho_validate;